@@ -0,0 +1,253 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{ClientId, RoomId, utils::unix_now};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Default lifetime of a freshly issued room-access token.
+pub const TOKEN_TTL_SECS: u64 = 3600;
+
+#[derive(Debug)]
+pub enum AuthError {
+    Malformed,
+    Expired,
+    WrongRoomOrClient,
+    BadSignature,
+}
+
+impl AuthError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            AuthError::Malformed => "malformed access token",
+            AuthError::Expired => "access token expired",
+            AuthError::WrongRoomOrClient => "access token does not match room or client",
+            AuthError::BadSignature => "access token failed verification",
+        }
+    }
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Issues and verifies opaque, expiring tokens that authorize a specific
+/// client to act within a specific room. Each token is an HMAC over
+/// `(room_id, client_id, not_after)` keyed by a server-wide secret, so
+/// validity can be checked without any shared state beyond that secret -
+/// room IDs alone (sequential and reused) are not enough to forge one.
+#[derive(Clone)]
+pub struct Authenticator {
+    secret: Vec<u8>,
+}
+
+impl Authenticator {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            secret: secret.into(),
+        }
+    }
+
+    /// Reads the room ID a token claims, without checking its signature.
+    /// Useful for routing a request to the right node before the token
+    /// can actually be verified there; never trust this for authorization.
+    pub fn unverified_room_id(token: &str) -> Option<RoomId> {
+        token.split('.').next()?.parse().ok()
+    }
+
+    pub fn issue(&self, room_id: RoomId, client_id: ClientId) -> String {
+        let not_after = unix_now() + TOKEN_TTL_SECS;
+        let mac = self.sign(room_id, client_id, not_after);
+        format!(
+            "{room_id}.{client_id}.{not_after}.{}",
+            URL_SAFE_NO_PAD.encode(mac)
+        )
+    }
+
+    pub fn verify(&self, token: &str, room_id: RoomId, client_id: ClientId) -> Result<(), AuthError> {
+        let claims = Self::decode(token)?;
+        if claims.room_id != room_id || claims.client_id != client_id {
+            return Err(AuthError::WrongRoomOrClient);
+        }
+        self.check(&claims)
+    }
+
+    /// Verifies a token was legitimately issued for `room_id`, without
+    /// requiring it to have been issued to any particular client. Lets a
+    /// room's creator share their own token as an invite: whoever holds
+    /// it can join as themselves, but still can't forge one for a room
+    /// they were never given access to.
+    pub fn verify_room(&self, token: &str, room_id: RoomId) -> Result<(), AuthError> {
+        let claims = Self::decode(token)?;
+        if claims.room_id != room_id {
+            return Err(AuthError::WrongRoomOrClient);
+        }
+        self.check(&claims)
+    }
+
+    fn decode(token: &str) -> Result<Claims, AuthError> {
+        let mut parts = token.split('.');
+        let (Some(tok_room), Some(tok_client), Some(tok_not_after), Some(tok_mac)) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(AuthError::Malformed);
+        };
+
+        let (Ok(room_id), Ok(client_id), Ok(not_after)) = (
+            tok_room.parse::<RoomId>(),
+            tok_client.parse::<ClientId>(),
+            tok_not_after.parse::<u64>(),
+        ) else {
+            return Err(AuthError::Malformed);
+        };
+        let Ok(mac) = URL_SAFE_NO_PAD.decode(tok_mac) else {
+            return Err(AuthError::Malformed);
+        };
+
+        Ok(Claims {
+            room_id,
+            client_id,
+            not_after,
+            mac,
+        })
+    }
+
+    /// Checks expiry and verifies the signature in constant time; does
+    /// not check which room/client the token claims to be for - callers
+    /// do that first so they can return the more specific `WrongRoomOrClient`.
+    fn check(&self, claims: &Claims) -> Result<(), AuthError> {
+        if claims.not_after < unix_now() {
+            return Err(AuthError::Expired);
+        }
+        self.mac_for(claims.room_id, claims.client_id, claims.not_after)
+            .verify_slice(&claims.mac)
+            .map_err(|_| AuthError::BadSignature)
+    }
+
+    fn mac_for(&self, room_id: RoomId, client_id: ClientId, not_after: u64) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts a key of any length");
+        mac.update(&room_id.to_be_bytes());
+        mac.update(&client_id.to_be_bytes());
+        mac.update(&not_after.to_be_bytes());
+        mac
+    }
+
+    fn sign(&self, room_id: RoomId, client_id: ClientId, not_after: u64) -> Vec<u8> {
+        self.mac_for(room_id, client_id, not_after)
+            .finalize()
+            .into_bytes()
+            .to_vec()
+    }
+}
+
+struct Claims {
+    room_id: RoomId,
+    client_id: ClientId,
+    not_after: u64,
+    mac: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> Authenticator {
+        Authenticator::new(b"test-secret".to_vec())
+    }
+
+    #[test]
+    fn verify_accepts_a_freshly_issued_token() {
+        let auth = auth();
+        let token = auth.issue(1, 42);
+        assert!(auth.verify(&token, 1, 42).is_ok());
+    }
+
+    #[test]
+    fn verify_rejects_a_token_for_a_different_client() {
+        let auth = auth();
+        let token = auth.issue(1, 42);
+        assert!(matches!(
+            auth.verify(&token, 1, 99),
+            Err(AuthError::WrongRoomOrClient)
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_a_token_for_a_different_room() {
+        let auth = auth();
+        let token = auth.issue(1, 42);
+        assert!(matches!(
+            auth.verify(&token, 2, 42),
+            Err(AuthError::WrongRoomOrClient)
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_an_expired_token() {
+        let auth = auth();
+        let not_after = unix_now() - 1;
+        let mac = auth.sign(1, 42, not_after);
+        let token = format!("1.42.{not_after}.{}", URL_SAFE_NO_PAD.encode(mac));
+        assert!(matches!(auth.verify(&token, 1, 42), Err(AuthError::Expired)));
+    }
+
+    #[test]
+    fn verify_rejects_a_tampered_token() {
+        let auth = auth();
+        let token = auth.issue(1, 42);
+        // Flip the claimed client_id without re-signing, as an attacker
+        // holding a valid token for themselves would have to.
+        let forged = token.replacen("42", "43", 1);
+        assert!(auth.verify(&forged, 1, 43).is_err());
+    }
+
+    #[test]
+    fn verify_rejects_a_token_signed_with_a_different_secret() {
+        let auth = auth();
+        let other = Authenticator::new(b"other-secret".to_vec());
+        let token = other.issue(1, 42);
+        assert!(matches!(
+            auth.verify(&token, 1, 42),
+            Err(AuthError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn verify_room_accepts_any_client_presenting_a_valid_room_token() {
+        let auth = auth();
+        // The inviter's own token doubles as the room's invite: anyone
+        // holding it can join as themselves via verify_room.
+        let invite = auth.issue(1, 42);
+        assert!(auth.verify_room(&invite, 1).is_ok());
+    }
+
+    #[test]
+    fn verify_room_allows_the_same_invite_to_be_reused_by_multiple_clients() {
+        let auth = auth();
+        let invite = auth.issue(1, 42);
+        assert!(auth.verify_room(&invite, 1).is_ok());
+        assert!(auth.verify_room(&invite, 1).is_ok());
+    }
+
+    #[test]
+    fn verify_room_rejects_a_token_for_a_different_room() {
+        let auth = auth();
+        let invite = auth.issue(1, 42);
+        assert!(matches!(
+            auth.verify_room(&invite, 2),
+            Err(AuthError::WrongRoomOrClient)
+        ));
+    }
+
+    #[test]
+    fn unverified_room_id_reads_the_claimed_room_without_checking_the_signature() {
+        let forged = "5.0.0.not-a-real-signature";
+        assert_eq!(Authenticator::unverified_room_id(forged), Some(5));
+    }
+}
@@ -0,0 +1,63 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::{ClientId, RoomId};
+
+pub type Sender = mpsc::UnboundedSender<RoomEvent>;
+pub type Receiver = mpsc::UnboundedReceiver<RoomEvent>;
+
+/// Events pushed over a subscriber's WebSocket when their room changes.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum RoomEvent {
+    PeerJoined {
+        client_id: ClientId,
+        punch_endpoint: Option<SocketAddr>,
+    },
+    PeerLeft {
+        client_id: ClientId,
+    },
+    RelayFallback,
+}
+
+/// Fans room events out to every client currently subscribed to that
+/// room, over one `mpsc` channel per subscriber. A subscriber is dropped
+/// the first time a send to it fails, which happens once its receiving
+/// end (and therefore its connection) has gone away.
+#[derive(Default)]
+pub struct Broadcaster {
+    subscribers: HashMap<RoomId, Vec<(ClientId, Sender)>>,
+}
+
+impl Broadcaster {
+    pub fn subscribe(&mut self, room_id: RoomId, client_id: ClientId) -> Receiver {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscribers
+            .entry(room_id)
+            .or_default()
+            .push((client_id, tx));
+        rx
+    }
+
+    /// Sends `event` to every subscriber of `room_id` other than `except`.
+    pub fn broadcast(&mut self, room_id: RoomId, except: ClientId, event: RoomEvent) {
+        let Some(subs) = self.subscribers.get_mut(&room_id) else {
+            return;
+        };
+        subs.retain(|(id, tx)| *id == except || tx.send(event.clone()).is_ok());
+    }
+
+    /// Sends `event` to every subscriber of `room_id`, with no exclusion.
+    pub fn broadcast_all(&mut self, room_id: RoomId, event: RoomEvent) {
+        let Some(subs) = self.subscribers.get_mut(&room_id) else {
+            return;
+        };
+        subs.retain(|(_, tx)| tx.send(event.clone()).is_ok());
+    }
+
+    pub fn drop_room(&mut self, room_id: RoomId) {
+        self.subscribers.remove(&room_id);
+    }
+}
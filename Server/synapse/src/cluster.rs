@@ -0,0 +1,129 @@
+use std::{path::Path, time::Duration};
+
+use serde::Deserialize;
+
+use crate::RoomId;
+
+/// How long to wait on a call to another node before giving up. Keeps a
+/// slow or unreachable peer node from stalling whatever on this node is
+/// waiting on the call (e.g. a forwarded `join_room` or a spawned punch
+/// notification task).
+const CLUSTER_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A contiguous range of room IDs owned by one node in the cluster.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NodeRange {
+    /// Inclusive start of the room ID range this node owns.
+    pub start: RoomId,
+    /// Exclusive end of the range.
+    pub end: RoomId,
+    /// Base HTTP address other nodes use to forward client-facing calls.
+    pub http_addr: String,
+    /// Address other nodes tunnel relay traffic to for this range.
+    pub relay_addr: String,
+    /// This node's UDP punch port, advertised to clients in
+    /// `create_room`/`join_room` responses so a client always sends its
+    /// punch registrations straight to the node that actually owns its
+    /// room, instead of guessing and possibly landing on a node with no
+    /// way to complete the registration for a room it doesn't own.
+    pub punch_addr: String,
+    /// Base address for this node's `/internal/*` routes. Separate from
+    /// `http_addr` so the internal API is never reachable through the
+    /// client-facing listener.
+    pub internal_addr: String,
+}
+
+/// Static, read-only map from room ID ranges to the node that owns them,
+/// loaded once at startup. Rooms are never re-sharded at runtime, so a
+/// client can always be told which node to talk to just from its room ID.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClusterMetadata {
+    /// This node's own `http_addr`, as it appears in `nodes` - used to
+    /// tell a locally-owned room from a remote one.
+    pub self_addr: String,
+    pub nodes: Vec<NodeRange>,
+}
+
+impl ClusterMetadata {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&raw)?)
+    }
+
+    fn owner(&self, room_id: RoomId) -> Option<&NodeRange> {
+        self.nodes
+            .iter()
+            .find(|n| room_id >= n.start && room_id < n.end)
+    }
+
+    /// The node range this node itself owns, used to allocate new room
+    /// IDs without colliding with another node's range.
+    pub fn own_range(&self) -> Option<&NodeRange> {
+        self.nodes.iter().find(|n| n.http_addr == self.self_addr)
+    }
+
+    /// The range that owns `room_id`, if it isn't this node.
+    pub fn remote_owner(&self, room_id: RoomId) -> Option<&NodeRange> {
+        let owner = self.owner(room_id)?;
+        if owner.http_addr == self.self_addr {
+            None
+        } else {
+            Some(owner)
+        }
+    }
+}
+
+/// A lightweight HTTP client that forwards client-facing calls to
+/// whichever node actually owns a room, so the client-facing API itself
+/// doesn't need to know about clustering.
+#[derive(Clone)]
+pub struct ClusterClient {
+    http: reqwest::Client,
+    /// Sent as `X-Cluster-Secret` on every call to another node's
+    /// `/internal/*` routes.
+    secret: Option<String>,
+}
+
+impl ClusterClient {
+    pub fn new(secret: Option<String>) -> Self {
+        Self {
+            http: reqwest::Client::builder()
+                .timeout(CLUSTER_REQUEST_TIMEOUT)
+                .build()
+                .expect("building a reqwest client with only a timeout set cannot fail"),
+            secret,
+        }
+    }
+
+    /// Forwards a `join_room`/`leave_room` style call verbatim and
+    /// returns the owning node's JSON response.
+    pub async fn forward<T: serde::de::DeserializeOwned>(
+        &self,
+        url: String,
+        body: &impl serde::Serialize,
+    ) -> anyhow::Result<T> {
+        let resp = self
+            .http
+            .post(url)
+            .json(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(resp.json().await?)
+    }
+
+    /// Asks the owning node for a client's last known punch endpoint.
+    pub async fn peer_endpoint(
+        &self,
+        internal_addr: &str,
+        client_id: u128,
+    ) -> anyhow::Result<Option<std::net::SocketAddr>> {
+        let url = format!("{internal_addr}/internal/punch_endpoint/{client_id}");
+        let mut req = self.http.get(url);
+        if let Some(secret) = &self.secret {
+            req = req.header("X-Cluster-Secret", secret);
+        }
+        let resp = req.send().await?.error_for_status()?;
+        Ok(resp.json().await?)
+    }
+}
@@ -0,0 +1,50 @@
+use axum::{
+    Json, http::StatusCode, response::IntoResponse, response::Response,
+};
+use serde::Serialize;
+
+pub type ApiResult<T> = Result<Json<T>, ApiError>;
+
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(&'static str),
+    Unauthorized(&'static str),
+    Conflict(&'static str),
+    NotFound(&'static str),
+    Internal(&'static str),
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: &'static str,
+}
+
+impl From<crate::auth::AuthError> for ApiError {
+    fn from(err: crate::auth::AuthError) -> Self {
+        ApiError::Unauthorized(err.message())
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        tracing::error!("internal error: {err:#}");
+        ApiError::Internal("internal server error")
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
+            ApiError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg),
+            ApiError::Conflict(msg) => (StatusCode::CONFLICT, msg),
+            ApiError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
+            ApiError::Internal(msg) => {
+                tracing::error!("internal error: {msg}");
+                (StatusCode::INTERNAL_SERVER_ERROR, msg)
+            }
+        };
+
+        (status, Json(ErrorBody { error: message })).into_response()
+    }
+}
@@ -1,7 +1,12 @@
 use axum::{
     Json, Router,
-    extract::{Path, State},
-    routing::post,
+    extract::{
+        Path, Query, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
 };
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, sync::Arc};
@@ -10,14 +15,25 @@ use tower_http::trace::TraceLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use crate::{
+    auth::{AuthError, Authenticator},
+    broadcast::{Broadcaster, RoomEvent},
+    cluster::{ClusterClient, ClusterMetadata},
     error::{ApiError, ApiResult},
+    metrics::Metrics,
     punch::punch_coordinator,
     relay::relay_server,
+    storage::Storage,
 };
+use tokio::sync::Mutex;
 
+mod auth;
+mod broadcast;
+mod cluster;
 mod error;
+mod metrics;
 mod punch;
 mod relay;
+mod storage;
 mod utils;
 
 type RoomId = u64;
@@ -27,33 +43,60 @@ type ClientString = String;
 #[derive(Clone)]
 struct AppState {
     inner: Arc<RwLock<AppStateInner>>,
+    metrics: Arc<Metrics>,
+    storage: Storage,
+    broadcaster: Arc<Mutex<Broadcaster>>,
+    auth: Arc<Authenticator>,
+    cluster: Option<Arc<ClusterMetadata>>,
+    cluster_client: ClusterClient,
+    /// Shared secret other nodes must present to call this node's
+    /// `/internal/*` routes. `None` means clustering isn't configured.
+    cluster_secret: Option<Arc<str>>,
 }
 
 struct AppStateInner {
     rooms: HashMap<RoomId, RoomState>,
     clients: HashMap<ClientId, ClientState>,
+    /// Last known punch endpoint per client, shared so other nodes in a
+    /// cluster can ask this one about clients it coordinated punching for.
+    punch_endpoints: HashMap<ClientId, SocketAddr>,
+    /// Next room ID to hand out on this node. Monotonically increasing so
+    /// a removed room's ID is never reused and silently aliased. In a
+    /// cluster, `create_room` clamps this up to the start of the node's
+    /// own range before handing out an ID, so it doubles as the
+    /// high-water mark within that range too.
+    next_room_id: RoomId,
 }
 
 impl AppStateInner {
-    pub fn get_peer(&self, client_id: ClientId) -> Option<ClientId> {
+    pub fn room_of(&self, client_id: ClientId) -> Option<RoomId> {
+        self.clients.get(&client_id).map(|c| c.room)
+    }
+
+    /// Every other member currently in `client_id`'s room.
+    pub fn get_peers(&self, client_id: ClientId) -> Option<Vec<ClientId>> {
         let client = self.clients.get(&client_id)?;
         let room = self.rooms.get(&client.room)?;
-        let guest_id = room.client?;
-        let other_id = if room.host == client_id {
-            guest_id
-        } else {
-            room.host
-        };
-        Some(other_id)
+        Some(
+            room.members
+                .iter()
+                .copied()
+                .filter(|&id| id != client_id)
+                .collect(),
+        )
     }
 }
 
 #[derive(Default)]
 struct RoomState {
-    host: ClientId,
-    client: Option<ClientId>,
+    /// Ordered room membership; the first entry is the host.
+    members: Vec<ClientId>,
 }
 
+/// Deliberately carries no credential of its own - admission to a room
+/// (first join or reconnect) is authorized by the caller's `Authenticator`
+/// token, checked in `join_room`/`leave_room`/`ws_upgrade`, never by
+/// anything stored here.
 struct ClientState {
     room: RoomId,
 }
@@ -76,6 +119,24 @@ struct Args {
     punch_port: u16,
     #[arg(long, default_value_t = 9002)]
     relay_port: u16,
+    #[arg(long, default_value = "rendezvous.db")]
+    db_path: std::path::PathBuf,
+    /// Key used to sign room-access tokens. Keep this stable across
+    /// restarts or every outstanding token is invalidated.
+    #[arg(long, env = "RENDEZVOUS_SECRET")]
+    secret: String,
+    /// Path to a cluster metadata JSON file mapping room ID ranges to
+    /// node addresses. Omit to run as a single standalone node.
+    #[arg(long)]
+    cluster_config: Option<std::path::PathBuf>,
+    /// Port serving `/internal/*` cluster-to-cluster routes. Never expose
+    /// this port to clients - it's separate from `http_port` on purpose.
+    #[arg(long, default_value_t = 9003)]
+    internal_port: u16,
+    /// Shared secret other nodes must send to call this node's internal
+    /// routes. Required once `cluster_config` is set.
+    #[arg(long, env = "RENDEZVOUS_CLUSTER_SECRET")]
+    cluster_secret: Option<String>,
 }
 
 fn bind_addr(port: u16) -> SocketAddr {
@@ -85,11 +146,48 @@ fn bind_addr(port: u16) -> SocketAddr {
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    if args.cluster_config.is_some() && args.cluster_secret.is_none() {
+        anyhow::bail!(
+            "--cluster-secret (or RENDEZVOUS_CLUSTER_SECRET) is required when --cluster-config is set - \
+             without it, /internal/* routes on --internal-port accept unauthenticated calls from anyone who can reach them"
+        );
+    }
 
-    let rooms = HashMap::new();
-    let clients = HashMap::new();
-    let inner = Arc::new(RwLock::new(AppStateInner { rooms, clients }));
-    let state = AppState { inner };
+    let storage = Storage::open(&args.db_path)?;
+    let (rooms, clients) = storage.load_all()?;
+    let next_room_id = rooms.keys().copied().max().map_or(0, |id| id + 1);
+    let metrics = Arc::new(Metrics::new()?);
+    // Gauges start at zero, but rooms/clients reloaded from storage
+    // already exist - seed them here or every later leave_room for one
+    // of these pre-existing rooms/clients decrements without a matching
+    // prior increment.
+    metrics.rooms_active.set(rooms.len() as i64);
+    metrics.clients_connected.set(clients.len() as i64);
+    let inner = Arc::new(RwLock::new(AppStateInner {
+        rooms,
+        clients,
+        punch_endpoints: HashMap::new(),
+        next_room_id,
+    }));
+    let broadcaster = Arc::new(Mutex::new(Broadcaster::default()));
+    let auth = Arc::new(Authenticator::new(args.secret.clone().into_bytes()));
+    let cluster = args
+        .cluster_config
+        .as_ref()
+        .map(ClusterMetadata::load)
+        .transpose()?
+        .map(Arc::new);
+    let cluster_secret: Option<Arc<str>> = args.cluster_secret.clone().map(Arc::from);
+    let state = AppState {
+        inner,
+        metrics,
+        storage,
+        broadcaster,
+        auth,
+        cluster,
+        cluster_client: ClusterClient::new(args.cluster_secret.clone()),
+        cluster_secret,
+    };
 
     tracing_subscriber::registry()
         .with(
@@ -107,22 +205,41 @@ async fn main() -> anyhow::Result<()> {
     let punch_addr = bind_addr(args.punch_port);
     let relay_addr = bind_addr(args.relay_port);
     let http_addr = bind_addr(args.http_port);
+    let internal_addr = bind_addr(args.internal_port);
 
     tracing::info!(
-        "Starting server with tcp port {} punch port {} relay port {}",
+        "Starting server with tcp port {} punch port {} relay port {} internal port {}",
         args.http_port,
         args.punch_port,
-        args.relay_port
+        args.relay_port,
+        args.internal_port
     );
 
     tokio::spawn(punch_coordinator(punch_addr, state.clone()));
     tokio::spawn(relay_server(relay_addr, state.clone()));
 
+    // Cluster-to-cluster routes live on their own listener so they can
+    // never be reached through the client-facing port.
+    let internal_app = Router::new()
+        .route(
+            "/internal/punch_endpoint/{client_id}",
+            get(get_punch_endpoint),
+        )
+        .with_state(state.clone());
+    let internal_listener = tokio::net::TcpListener::bind(internal_addr).await?;
+    tokio::spawn(async move {
+        if let Err(err) = axum::serve(internal_listener, internal_app).await {
+            tracing::error!("internal server failed: {err:#}");
+        }
+    });
+
     let app = Router::new()
         .layer(TraceLayer::new_for_http())
         .route("/create_room", post(create_room))
         .route("/join_room/{room_id}", post(join_room))
         .route("/leave_room", post(leave_room))
+        .route("/metrics", get(get_metrics))
+        .route("/ws/{client_id}", get(ws_upgrade))
         .with_state(state);
 
     let listener = tokio::net::TcpListener::bind(http_addr).await?;
@@ -130,6 +247,89 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn get_metrics(State(st): State<AppState>) -> Result<String, (StatusCode, String)> {
+    st.metrics
+        .encode()
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+#[derive(Deserialize)]
+struct WsAuthQuery {
+    token: String,
+}
+
+/// Subscribes a client to push notifications for the room it has already
+/// joined via `create_room`/`join_room`. Call after joining; reconnecting
+/// simply re-subscribes. Requires the caller's own access token, since
+/// the event stream includes peers' punch endpoints.
+async fn ws_upgrade(
+    State(st): State<AppState>,
+    Path(client_id): Path<ClientString>,
+    Query(query): Query<WsAuthQuery>,
+    ws: WebSocketUpgrade,
+) -> Result<impl IntoResponse, ApiError> {
+    let Ok(client_id) = client_id.parse::<u128>() else {
+        return Err(ApiError::BadRequest("could not parse client id"));
+    };
+    let room_id = {
+        let state = st.inner.read().await;
+        let Some(client) = state.clients.get(&client_id) else {
+            return Err(ApiError::NotFound("client not in a room"));
+        };
+        client.room
+    };
+    st.auth.verify(&query.token, room_id, client_id)?;
+
+    let rx = st.broadcaster.lock().await.subscribe(room_id, client_id);
+    Ok(ws.on_upgrade(move |socket| push_room_events(socket, rx)))
+}
+
+async fn push_room_events(mut socket: WebSocket, mut rx: broadcast::Receiver) {
+    while let Some(event) = rx.recv().await {
+        let Ok(payload) = serde_json::to_string(&event) else {
+            continue;
+        };
+        if socket.send(Message::Text(payload.into())).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Lets other nodes in a cluster ask this one for a client's last known
+/// punch endpoint, so peers split across nodes can still be introduced
+/// to each other. Only reachable via the internal listener, and, when a
+/// cluster secret is configured, still requires it so a node that can
+/// merely route to this port can't enumerate every client's endpoint.
+async fn get_punch_endpoint(
+    State(st): State<AppState>,
+    Path(client_id): Path<ClientString>,
+    headers: HeaderMap,
+) -> ApiResult<Option<SocketAddr>> {
+    check_cluster_secret(&st, &headers)?;
+    let Ok(client_id) = client_id.parse::<u128>() else {
+        return Err(ApiError::BadRequest("could not parse client id"));
+    };
+    let state = st.inner.read().await;
+    Ok(Json(state.punch_endpoints.get(&client_id).copied()))
+}
+
+/// Rejects a request to an internal route unless it carries the
+/// `X-Cluster-Secret` header matching this node's configured secret.
+/// With no secret configured, every request is accepted.
+fn check_cluster_secret(st: &AppState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(expected) = st.cluster_secret.as_deref() else {
+        return Ok(());
+    };
+    let given = headers
+        .get("x-cluster-secret")
+        .and_then(|v| v.to_str().ok());
+    if given == Some(expected) {
+        Ok(())
+    } else {
+        Err(ApiError::Unauthorized("missing or invalid cluster secret"))
+    }
+}
+
 #[derive(Deserialize)]
 struct CreateRoomReq {
     client_id: ClientString,
@@ -138,6 +338,13 @@ struct CreateRoomReq {
 #[derive(Serialize)]
 struct CreateRoomResp {
     room_id: RoomId,
+    token: String,
+    /// Where to send UDP punch registrations for this room. `None` when
+    /// running standalone, since the client already knows the single
+    /// node's punch port out of band; in a cluster, this is always the
+    /// address of the node that owns the room (this node, since
+    /// `create_room` always allocates from its own range).
+    punch_addr: Option<String>,
 }
 
 async fn create_room(
@@ -148,31 +355,74 @@ async fn create_room(
         return Err(ApiError::BadRequest("could not parse client id"));
     };
     let mut state = st.inner.write().await;
-    let room_id = state.rooms.len() as u64;
+    let room_id = match st.cluster.as_ref().and_then(|c| c.own_range()) {
+        Some(range) => {
+            // Same non-reuse guarantee as the standalone path below: track
+            // a high-water mark within the node's range instead of
+            // scanning for the lowest free slot, so a deleted room's ID
+            // can't be handed out again to alias an outstanding token
+            // issued for it.
+            let next = state.next_room_id.max(range.start);
+            if next >= range.end {
+                return Err(ApiError::Internal(
+                    "no free room ids left in this node's range",
+                ));
+            }
+            state.next_room_id = next + 1;
+            next
+        }
+        None => {
+            let id = state.next_room_id;
+            state.next_room_id += 1;
+            id
+        }
+    };
 
-    state.rooms.insert(
-        room_id,
-        RoomState {
-            host: client_id,
-            client: None,
-        },
-    );
+    let room = RoomState {
+        members: vec![client_id],
+    };
+    state.rooms.insert(room_id, room);
+    let client_state = ClientState::new(room_id);
+    st.storage.put_room(room_id, &state.rooms[&room_id])?;
+    st.storage.put_client(client_id, &client_state)?;
     state
         .clients
         .entry(client_id)
         .and_modify(|e| e.room = room_id)
-        .or_insert(ClientState::new(room_id));
+        .or_insert(client_state);
+    st.metrics.rooms_active.inc();
+    st.metrics.clients_connected.inc();
 
-    Ok(Json(CreateRoomResp { room_id }))
+    Ok(Json(CreateRoomResp {
+        room_id,
+        token: st.auth.issue(room_id, client_id),
+        punch_addr: st
+            .cluster
+            .as_ref()
+            .and_then(|c| c.own_range())
+            .map(|r| r.punch_addr.clone()),
+    }))
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 struct JoinRoomReq {
     client_id: ClientString,
+    /// For a reconnecting member, their own access token. For a new
+    /// member, the room's invite token (any valid token issued for this
+    /// room, e.g. the one `create_room`/`join_room` handed the inviter).
+    token: String,
 }
 
-#[derive(Serialize)]
-struct JoinRoomResp {}
+#[derive(Serialize, Deserialize)]
+struct JoinRoomResp {
+    token: String,
+    members: Vec<ClientString>,
+    /// See `CreateRoomResp::punch_addr`. When this response is relayed
+    /// back from a remote owner via `ClusterClient::forward`, it's
+    /// already the owner's own punch address - nothing extra to plumb
+    /// through here.
+    punch_addr: Option<String>,
+}
 
 async fn join_room(
     State(st): State<AppState>,
@@ -182,6 +432,13 @@ async fn join_room(
     let Ok(client_id) = req.client_id.parse::<u128>() else {
         return Err(ApiError::BadRequest("could not parse client id"));
     };
+
+    if let Some(owner) = st.cluster.as_ref().and_then(|c| c.remote_owner(room_id)) {
+        let url = format!("{}/join_room/{room_id}", owner.http_addr);
+        let resp = st.cluster_client.forward(url, &req).await?;
+        return Ok(Json(resp));
+    }
+
     let mut state = st.inner.write().await;
 
     if state
@@ -194,58 +451,124 @@ async fn join_room(
     let Some(room) = state.rooms.get_mut(&room_id) else {
         return Err(ApiError::NotFound("room not found"));
     };
-    if room.client.is_some() {
-        return Err(ApiError::Conflict("room is full"));
+
+    // Re-joining a room the client already belongs to (e.g. after the
+    // coordinator restarted and reloaded its state from storage) is a
+    // no-op rather than growing the membership list.
+    let already_member = room.members.contains(&client_id);
+    if already_member {
+        // Reconnecting as an existing member normally requires that
+        // member's own token, so a stranger can't hijack their slot just
+        // by sending their client_id. But a member who went quiet for
+        // longer than TOKEN_TTL_SECS has no way to mint themselves a new
+        // one, so fall back to accepting any currently-valid token for
+        // the room (the same proof-of-invite a brand-new member needs)
+        // rather than locking them out of their own slot forever.
+        if let Err(err) = st.auth.verify(&req.token, room_id, client_id) {
+            if !matches!(err, AuthError::Expired) {
+                return Err(err.into());
+            }
+            st.auth.verify_room(&req.token, room_id)?;
+        }
+    } else {
+        // A brand-new member just needs any valid token for this room -
+        // proof they were actually given an invite, not merely a guess
+        // at the room_id.
+        st.auth.verify_room(&req.token, room_id)?;
+        room.members.push(client_id);
     }
+    let members = room.members.clone();
 
-    room.client = Some(client_id);
     state
         .clients
         .entry(client_id)
         .and_modify(|e| e.room = room_id)
-        .or_insert(ClientState::new(room_id));
+        .or_insert_with(|| ClientState::new(room_id));
+    st.storage.put_room(room_id, &state.rooms[&room_id])?;
+    st.storage.put_client(client_id, &state.clients[&client_id])?;
+    if !already_member {
+        st.metrics.clients_connected.inc();
+        st.broadcaster.lock().await.broadcast(
+            room_id,
+            client_id,
+            RoomEvent::PeerJoined {
+                client_id,
+                punch_endpoint: None,
+            },
+        );
+    }
 
-    Ok(Json(JoinRoomResp {}))
+    Ok(Json(JoinRoomResp {
+        token: st.auth.issue(room_id, client_id),
+        members: members.into_iter().map(|id| id.to_string()).collect(),
+        punch_addr: st
+            .cluster
+            .as_ref()
+            .and_then(|c| c.own_range())
+            .map(|r| r.punch_addr.clone()),
+    }))
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 struct LeaveRoomReq {
     client_id: ClientString,
+    token: String,
 }
 
 async fn leave_room(State(st): State<AppState>, Json(req): Json<LeaveRoomReq>) -> ApiResult<()> {
     let Ok(client_id) = req.client_id.parse::<u128>() else {
         return Err(ApiError::BadRequest("could not parse client id"));
     };
+
+    if let Some(cluster) = st.cluster.as_ref() {
+        let claimed_room = Authenticator::unverified_room_id(&req.token)
+            .ok_or(ApiError::BadRequest("could not parse access token"))?;
+        if let Some(owner) = cluster.remote_owner(claimed_room) {
+            let url = format!("{}/leave_room", owner.http_addr);
+            let resp = st.cluster_client.forward(url, &req).await?;
+            return Ok(Json(resp));
+        }
+    }
+
     let mut state = st.inner.write().await;
     let Some(client_state) = state.clients.get(&client_id) else {
         return Err(ApiError::NotFound("client not in a room"));
     };
     let cur_room = client_state.room;
+    // Same fallback as join_room's reconnect path: a member whose own
+    // token lapsed while they were gone can still prove they belong with
+    // any other currently-valid token for the room, rather than being
+    // stuck in the room forever with no way to leave.
+    if let Err(err) = st.auth.verify(&req.token, cur_room, client_id) {
+        if !matches!(err, AuthError::Expired) {
+            return Err(err.into());
+        }
+        st.auth.verify_room(&req.token, cur_room)?;
+    }
     let Some(room) = state.rooms.get_mut(&cur_room) else {
         return Err(ApiError::NotFound("client's room no longer exists"));
     };
 
-    if room.client.is_some_and(|id| id == client_id) {
-        // client was the client of the room
-        room.client = None;
+    if !room.members.contains(&client_id) {
+        return Err(ApiError::Internal("client's cached room was incorrect"));
+    }
+    room.members.retain(|&id| id != client_id);
+
+    if room.members.is_empty() {
+        state.rooms.remove(&cur_room);
+        st.storage.remove_room(cur_room)?;
+        st.broadcaster.lock().await.drop_room(cur_room);
+        st.metrics.rooms_active.dec();
     } else {
-        if client_id != room.host {
-            return Err(ApiError::Internal("client's cached room was incorrect"));
-        }
-        // client was the host of the room
-        match room.client {
-            // if there was a peer, that peer becomes the host
-            Some(peer) => {
-                room.host = peer;
-                room.client = None;
-            }
-            // otherwise, the room is empty, and should be removed
-            None => {
-                state.rooms.remove(&cur_room);
-            }
-        }
+        st.storage.put_room(cur_room, &state.rooms[&cur_room])?;
+        st.broadcaster
+            .lock()
+            .await
+            .broadcast(cur_room, client_id, RoomEvent::PeerLeft { client_id });
     }
     state.clients.remove(&client_id);
+    state.punch_endpoints.remove(&client_id);
+    st.storage.remove_client(client_id)?;
+    st.metrics.clients_connected.dec();
     Ok(Json(()))
 }
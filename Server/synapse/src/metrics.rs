@@ -0,0 +1,57 @@
+use prometheus::{Encoder, IntCounter, IntGauge, Registry, TextEncoder};
+
+/// Process-wide counters and gauges for the rendezvous server, registered
+/// against a single `Registry` and rendered on the `/metrics` route.
+pub struct Metrics {
+    registry: Registry,
+    pub rooms_active: IntGauge,
+    pub clients_connected: IntGauge,
+    pub punch_attempts_total: IntCounter,
+    pub punch_successes_total: IntCounter,
+    pub relay_bytes_total: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> anyhow::Result<Self> {
+        let registry = Registry::new();
+
+        let rooms_active = IntGauge::new("rooms_active", "Number of rooms currently open")?;
+        let clients_connected =
+            IntGauge::new("clients_connected", "Number of clients currently in a room")?;
+        let punch_attempts_total = IntCounter::new(
+            "punch_attempts_total",
+            "Number of hole punch attempts coordinated",
+        )?;
+        let punch_successes_total = IntCounter::new(
+            "punch_successes_total",
+            "Number of peers found for hole punch attempts, counted once per peer",
+        )?;
+        let relay_bytes_total = IntCounter::new(
+            "relay_bytes_total",
+            "Bytes relayed between peers after a failed hole punch",
+        )?;
+
+        registry.register(Box::new(rooms_active.clone()))?;
+        registry.register(Box::new(clients_connected.clone()))?;
+        registry.register(Box::new(punch_attempts_total.clone()))?;
+        registry.register(Box::new(punch_successes_total.clone()))?;
+        registry.register(Box::new(relay_bytes_total.clone()))?;
+
+        Ok(Self {
+            registry,
+            rooms_active,
+            clients_connected,
+            punch_attempts_total,
+            punch_successes_total,
+            relay_bytes_total,
+        })
+    }
+
+    /// Renders all registered metrics in Prometheus text exposition format.
+    pub fn encode(&self) -> anyhow::Result<String> {
+        let metric_families = self.registry.gather();
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&metric_families, &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
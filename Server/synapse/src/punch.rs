@@ -0,0 +1,151 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use tokio::{net::UdpSocket, sync::Mutex};
+
+use crate::{AppState, RoomId, broadcast::RoomEvent};
+
+#[derive(Debug, Deserialize)]
+struct PunchRegister {
+    client_id: u128,
+    token: String,
+}
+
+#[derive(Debug, Serialize)]
+struct PunchEndpoint {
+    addr: SocketAddr,
+}
+
+/// Listens for UDP registration packets from clients and, as members of
+/// a room register, tells each newly registered client and its peers
+/// each other's public endpoints so they can attempt a hole punch
+/// directly. In a
+/// cluster, `create_room`/`join_room` hand the client the punch address
+/// of the node that actually owns the room, so registrations for a given
+/// room always land here on that room's owning node - `room_of`/
+/// `get_peers` below only ever need to resolve local state.
+pub async fn punch_coordinator(addr: SocketAddr, state: AppState) -> anyhow::Result<()> {
+    let socket = Arc::new(UdpSocket::bind(addr).await?);
+    let registered: Arc<Mutex<HashMap<u128, SocketAddr>>> = Arc::default();
+    let mut buf = [0u8; 1024];
+
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let Ok(msg) = serde_json::from_slice::<PunchRegister>(&buf[..len]) else {
+            tracing::debug!("dropping malformed punch packet from {from}");
+            continue;
+        };
+
+        state.metrics.punch_attempts_total.inc();
+
+        let (room_id, peer_ids) = {
+            let inner = state.inner.read().await;
+            let Some(room_id) = inner.room_of(msg.client_id) else {
+                continue;
+            };
+            let Some(peer_ids) = inner.get_peers(msg.client_id) else {
+                continue;
+            };
+            (room_id, peer_ids)
+        };
+
+        if let Err(err) = state.auth.verify(&msg.token, room_id, msg.client_id) {
+            tracing::debug!("rejecting punch registration from {from}: {err:?}");
+            continue;
+        }
+        registered.lock().await.insert(msg.client_id, from);
+        state
+            .inner
+            .write()
+            .await
+            .punch_endpoints
+            .insert(msg.client_id, from);
+
+        // Notifying peers can involve asking a remote node for an
+        // endpoint over HTTP, which must not block this task's next
+        // `recv_from` - that would stall punch coordination for every
+        // client on this node behind one slow or unreachable peer.
+        let state = state.clone();
+        let socket = Arc::clone(&socket);
+        let registered = Arc::clone(&registered);
+        tokio::spawn(async move {
+            if let Err(err) =
+                notify_peers(&state, &socket, &registered, room_id, msg.client_id, from, peer_ids)
+                    .await
+            {
+                tracing::warn!(
+                    "failed to notify peers of punch registration for {}: {err:#}",
+                    msg.client_id
+                );
+            }
+        });
+    }
+}
+
+/// Tells `client_id` the public endpoint of each of its peers whose
+/// endpoint is known, and tells each peer registered locally
+/// `client_id`'s endpoint in turn.
+async fn notify_peers(
+    state: &AppState,
+    socket: &UdpSocket,
+    registered: &Mutex<HashMap<u128, SocketAddr>>,
+    room_id: RoomId,
+    client_id: u128,
+    from: SocketAddr,
+    peer_ids: Vec<u128>,
+) -> anyhow::Result<()> {
+    for peer_id in peer_ids {
+        let local_addr = registered.lock().await.get(&peer_id).copied();
+        let peer_addr = match local_addr {
+            Some(addr) => Some(addr),
+            None => remote_peer_endpoint(state, room_id, peer_id).await,
+        };
+        let Some(peer_addr) = peer_addr else {
+            continue;
+        };
+
+        send_endpoint(socket, from, peer_addr).await?;
+        if registered.lock().await.contains_key(&peer_id) {
+            send_endpoint(socket, peer_addr, from).await?;
+        }
+        state.metrics.punch_successes_total.inc();
+        state.broadcaster.lock().await.broadcast(
+            room_id,
+            client_id,
+            RoomEvent::PeerJoined {
+                client_id,
+                punch_endpoint: Some(from),
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Looks up a peer's punch endpoint on the node that actually owns its
+/// room. Normally unreachable, since a room's members all register with
+/// its owning node directly; kept as a fallback for a peer still holding
+/// a punch address from before a cluster topology change.
+async fn remote_peer_endpoint(
+    state: &AppState,
+    room_id: crate::RoomId,
+    peer_id: u128,
+) -> Option<SocketAddr> {
+    let owner = state.cluster.as_ref()?.remote_owner(room_id)?;
+    match state
+        .cluster_client
+        .peer_endpoint(&owner.internal_addr, peer_id)
+        .await
+    {
+        Ok(addr) => addr,
+        Err(err) => {
+            tracing::debug!("failed to fetch remote punch endpoint for {peer_id}: {err:#}");
+            None
+        }
+    }
+}
+
+async fn send_endpoint(socket: &UdpSocket, to: SocketAddr, endpoint: SocketAddr) -> anyhow::Result<()> {
+    let payload = serde_json::to_vec(&PunchEndpoint { addr: endpoint })?;
+    socket.send_to(&payload, to).await?;
+    Ok(())
+}
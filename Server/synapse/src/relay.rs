@@ -0,0 +1,264 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{Mutex, oneshot},
+    time,
+};
+
+use crate::{AppState, RoomId, broadcast::RoomEvent};
+
+/// How long a peer that arrives first waits for its counterpart before
+/// the relay gives up on the room.
+const PARK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The first peer to arrive for a room, waiting to be handed its
+/// counterpart's stream.
+struct Parked {
+    notify: oneshot::Sender<TcpStream>,
+}
+
+#[derive(Default)]
+struct Rendezvous {
+    parked: HashMap<RoomId, Parked>,
+    /// Bytes the parked peer already sent before its counterpart showed
+    /// up, so nothing written while waiting gets dropped.
+    pending_data: HashMap<RoomId, Vec<u8>>,
+}
+
+/// Bridges two peers' TCP connections when hole punching fails. Peers no
+/// longer have to connect simultaneously: whoever arrives first parks
+/// here, has its early bytes buffered, and gets spliced to the second
+/// peer's stream as soon as it shows up (or is dropped after
+/// `PARK_TIMEOUT` if it never does).
+pub async fn relay_server(addr: SocketAddr, state: AppState) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let rendezvous: Arc<Mutex<Rendezvous>> = Arc::default();
+
+    loop {
+        let (socket, peer_addr) = listener.accept().await?;
+        let state = state.clone();
+        let rendezvous = rendezvous.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(socket, state, rendezvous).await {
+                tracing::warn!("relay connection from {peer_addr} failed: {err:#}");
+            }
+        });
+    }
+}
+
+/// Reads the handshake a relay client sends before anything gets spliced:
+/// the room to join, the sender's client ID, and an access token proving
+/// they're actually a member of that room.
+async fn read_handshake(socket: &mut TcpStream) -> anyhow::Result<(RoomId, u128, String)> {
+    let room_id = socket.read_u64().await?;
+    let client_id = socket.read_u128().await?;
+    let token_len = socket.read_u16().await?;
+    let mut token_buf = vec![0u8; token_len as usize];
+    socket.read_exact(&mut token_buf).await?;
+    let token = String::from_utf8(token_buf)?;
+    Ok((room_id, client_id, token))
+}
+
+async fn handle_connection(
+    mut socket: TcpStream,
+    state: AppState,
+    rendezvous: Arc<Mutex<Rendezvous>>,
+) -> anyhow::Result<()> {
+    let (room_id, client_id, token) = read_handshake(&mut socket).await?;
+    state.auth.verify(&token, room_id, client_id)?;
+
+    if let Some(owner) = state.cluster.as_ref().and_then(|c| c.remote_owner(room_id)) {
+        return tunnel_to_owner(&owner.relay_addr, room_id, client_id, &token, &mut socket, &state)
+            .await;
+    }
+
+    let parked = rendezvous.lock().await.parked.remove(&room_id);
+
+    let mut other = match parked {
+        Some(parked) => {
+            // We're the second peer for this room; hand our stream to
+            // whichever task is waiting on it and let that task relay.
+            let _ = parked.notify.send(socket);
+            return Ok(());
+        }
+        None => {
+            let (tx, rx) = oneshot::channel();
+            {
+                let mut rendezvous = rendezvous.lock().await;
+                rendezvous.parked.insert(room_id, Parked { notify: tx });
+                rendezvous.pending_data.entry(room_id).or_default();
+            }
+
+            let mut other = wait_for_counterpart(room_id, &mut socket, &rendezvous, rx).await?;
+            let pending = rendezvous
+                .lock()
+                .await
+                .pending_data
+                .remove(&room_id)
+                .unwrap_or_default();
+            if !pending.is_empty() {
+                other.write_all(&pending).await?;
+            }
+            other
+        }
+    };
+
+    state
+        .broadcaster
+        .lock()
+        .await
+        .broadcast_all(room_id, RoomEvent::RelayFallback);
+
+    let (from_socket, from_other) = tokio::io::copy_bidirectional(&mut socket, &mut other).await?;
+    state
+        .metrics
+        .relay_bytes_total
+        .inc_by(from_socket + from_other);
+    Ok(())
+}
+
+/// Forwards a relay connection to the node that actually owns `room_id`,
+/// replaying the same handshake so the owning node can park or splice it
+/// exactly as if the client had connected there directly.
+async fn tunnel_to_owner(
+    relay_addr: &str,
+    room_id: RoomId,
+    client_id: u128,
+    token: &str,
+    socket: &mut TcpStream,
+    state: &AppState,
+) -> anyhow::Result<()> {
+    let mut upstream = TcpStream::connect(relay_addr).await?;
+    upstream.write_u64(room_id).await?;
+    upstream.write_u128(client_id).await?;
+    upstream.write_u16(token.len() as u16).await?;
+    upstream.write_all(token.as_bytes()).await?;
+
+    let (from_socket, from_upstream) =
+        tokio::io::copy_bidirectional(socket, &mut upstream).await?;
+    state
+        .metrics
+        .relay_bytes_total
+        .inc_by(from_socket + from_upstream);
+    Ok(())
+}
+
+/// Waits for the counterpart to connect, buffering whatever the parked
+/// peer sends in the meantime so it can be replayed once splicing
+/// starts. Gives up after `PARK_TIMEOUT`.
+async fn wait_for_counterpart(
+    room_id: RoomId,
+    socket: &mut TcpStream,
+    rendezvous: &Arc<Mutex<Rendezvous>>,
+    mut rx: oneshot::Receiver<TcpStream>,
+) -> anyhow::Result<TcpStream> {
+    let mut buf = [0u8; 4096];
+    let deadline = time::sleep(PARK_TIMEOUT);
+    tokio::pin!(deadline);
+
+    loop {
+        tokio::select! {
+            result = &mut rx => {
+                return Ok(result?);
+            }
+            read = socket.read(&mut buf) => {
+                let n = read?;
+                if n == 0 {
+                    let mut rendezvous = rendezvous.lock().await;
+                    rendezvous.parked.remove(&room_id);
+                    rendezvous.pending_data.remove(&room_id);
+                    anyhow::bail!("peer disconnected while waiting for its counterpart");
+                }
+                rendezvous
+                    .lock()
+                    .await
+                    .pending_data
+                    .entry(room_id)
+                    .or_default()
+                    .extend_from_slice(&buf[..n]);
+            }
+            _ = &mut deadline => {
+                let mut rendezvous = rendezvous.lock().await;
+                rendezvous.parked.remove(&room_id);
+                rendezvous.pending_data.remove(&room_id);
+                anyhow::bail!("timed out waiting for a counterpart in room {room_id}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A connected pair of real `TcpStream`s, since `wait_for_counterpart`
+    /// needs to read from one as a peer would.
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, (server, _)) =
+            tokio::join!(TcpStream::connect(addr), listener.accept());
+        (client.unwrap(), server.unwrap())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_for_counterpart_clears_parked_state_on_timeout() {
+        let rendezvous: Arc<Mutex<Rendezvous>> = Arc::default();
+        let room_id = 7;
+        rendezvous
+            .lock()
+            .await
+            .pending_data
+            .insert(room_id, vec![1, 2, 3]);
+        let (_client, mut server) = socket_pair().await;
+        let (_tx, rx) = oneshot::channel::<TcpStream>();
+
+        let err = wait_for_counterpart(room_id, &mut server, &rendezvous, rx)
+            .await
+            .unwrap_err();
+        // No real bytes or counterpart arrive, so this only resolves once
+        // we fast-forward the paused clock past PARK_TIMEOUT.
+        assert!(err.to_string().contains("timed out"));
+
+        let rendezvous = rendezvous.lock().await;
+        assert!(!rendezvous.parked.contains_key(&room_id));
+        assert!(!rendezvous.pending_data.contains_key(&room_id));
+    }
+
+    #[tokio::test]
+    async fn wait_for_counterpart_clears_pending_data_when_the_parked_peer_disconnects() {
+        let rendezvous: Arc<Mutex<Rendezvous>> = Arc::default();
+        let room_id = 9;
+        rendezvous.lock().await.pending_data.insert(room_id, vec![9]);
+        let (client, mut server) = socket_pair().await;
+        drop(client);
+
+        let (_tx, rx) = oneshot::channel::<TcpStream>();
+        let err = wait_for_counterpart(room_id, &mut server, &rendezvous, rx)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("disconnected"));
+
+        let rendezvous = rendezvous.lock().await;
+        assert!(!rendezvous.parked.contains_key(&room_id));
+        assert!(!rendezvous.pending_data.contains_key(&room_id));
+    }
+
+    #[tokio::test]
+    async fn wait_for_counterpart_returns_the_counterpart_once_it_arrives() {
+        let rendezvous: Arc<Mutex<Rendezvous>> = Arc::default();
+        let room_id = 11;
+        let (_client, mut server) = socket_pair().await;
+        let (tx, rx) = oneshot::channel::<TcpStream>();
+        let (counterpart, _counterpart_peer) = socket_pair().await;
+        tx.send(counterpart).unwrap();
+
+        assert!(
+            wait_for_counterpart(room_id, &mut server, &rendezvous, rx)
+                .await
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use std::{collections::HashMap, path::Path};
+
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::{ClientId, ClientState, RoomId, RoomState};
+
+/// Ordered migration steps; index + 1 is the schema version it produces.
+/// Never edit a step once it has shipped - appending a new one is the
+/// only way to change the schema, so a node that already ran the earlier
+/// steps can still pick up only what's new.
+const MIGRATIONS: &[&str] = &[
+    // v0 -> v1 (chunk0-2): the original two-peer room schema.
+    "
+    CREATE TABLE IF NOT EXISTS rooms (
+        room_id INTEGER PRIMARY KEY,
+        host TEXT NOT NULL,
+        client TEXT
+    );
+    CREATE TABLE IF NOT EXISTS clients (
+        client_id TEXT PRIMARY KEY,
+        room_id INTEGER NOT NULL,
+        reconnect_token TEXT NOT NULL
+    );
+    ",
+    // v1 -> v2 (chunk0-3 + chunk0-5): rooms grew past two peers (host/
+    // client collapse into a flat member list), and clients lost
+    // reconnect_token once real access tokens replaced it. SQLite can't
+    // ALTER/DROP a column portably across versions, so rebuild both
+    // tables instead.
+    "
+    CREATE TABLE rooms_v2 (
+        room_id INTEGER PRIMARY KEY,
+        members TEXT NOT NULL
+    );
+    INSERT INTO rooms_v2 (room_id, members)
+        SELECT room_id, host || COALESCE(',' || client, '') FROM rooms;
+    DROP TABLE rooms;
+    ALTER TABLE rooms_v2 RENAME TO rooms;
+
+    CREATE TABLE clients_v2 (
+        client_id TEXT PRIMARY KEY,
+        room_id INTEGER NOT NULL
+    );
+    INSERT INTO clients_v2 (client_id, room_id) SELECT client_id, room_id FROM clients;
+    DROP TABLE clients;
+    ALTER TABLE clients_v2 RENAME TO clients;
+    ",
+];
+
+/// Brings a database opened by any prior version of this binary up to
+/// `MIGRATIONS.len()`, recording progress in `schema_version` so a
+/// restart never re-runs a step that already happened.
+fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+    conn.execute_batch("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)")?;
+    let existing: Option<i64> = conn
+        .query_row("SELECT version FROM schema_version", [], |row| row.get(0))
+        .optional()?;
+    let mut version = match existing {
+        Some(v) => v,
+        None => bootstrap_version(conn),
+    };
+
+    let tx = conn.transaction()?;
+    for migration in &MIGRATIONS[version as usize..] {
+        tx.execute_batch(migration)?;
+        version += 1;
+    }
+    tx.execute("DELETE FROM schema_version", [])?;
+    tx.execute(
+        "INSERT INTO schema_version (version) VALUES (?1)",
+        params![version],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// A database with no `schema_version` row predates migration tracking,
+/// so its version has to be inferred from what's actually there: no
+/// `rooms` table at all means a brand new database (v0); a `rooms` table
+/// that still has chunk0-2's `host` column means it's stuck at v1;
+/// anything else was already rebuilt to the current schema by a binary
+/// that shipped before this migration tracking existed.
+fn bootstrap_version(conn: &Connection) -> i64 {
+    let table_exists = |name: &str| -> bool {
+        conn.query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            params![name],
+            |_| Ok(()),
+        )
+        .optional()
+        .unwrap_or(None)
+        .is_some()
+    };
+    if !table_exists("rooms") {
+        return 0;
+    }
+    let has_host_column = conn
+        .query_row(
+            "SELECT 1 FROM pragma_table_info('rooms') WHERE name = 'host'",
+            [],
+            |_| Ok(()),
+        )
+        .optional()
+        .unwrap_or(None)
+        .is_some();
+    if has_host_column { 1 } else { MIGRATIONS.len() as i64 }
+}
+
+fn encode_members(members: &[ClientId]) -> String {
+    members
+        .iter()
+        .map(ClientId::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn decode_members(raw: &str) -> anyhow::Result<Vec<ClientId>> {
+    if raw.is_empty() {
+        return Ok(Vec::new());
+    }
+    raw.split(',').map(|id| Ok(id.parse()?)).collect()
+}
+
+/// Write-through persistence for rooms and clients, backed by a pooled
+/// SQLite connection. State is reloaded from here on every boot so a
+/// server restart doesn't strand connected peers.
+#[derive(Clone)]
+pub struct Storage {
+    pool: Pool<SqliteConnectionManager>,
+}
+
+impl Storage {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let manager = SqliteConnectionManager::file(path);
+        let pool = Pool::new(manager)?;
+        migrate(&mut pool.get()?)?;
+        Ok(Self { pool })
+    }
+
+    /// Reloads every room and client row, for use at startup.
+    pub fn load_all(&self) -> anyhow::Result<(HashMap<RoomId, RoomState>, HashMap<ClientId, ClientState>)> {
+        let conn = self.pool.get()?;
+
+        let mut rooms = HashMap::new();
+        let mut stmt = conn.prepare("SELECT room_id, members FROM rooms")?;
+        let rows = stmt.query_map([], |row| {
+            let room_id: i64 = row.get(0)?;
+            let members: String = row.get(1)?;
+            Ok((room_id as RoomId, members))
+        })?;
+        for row in rows {
+            let (room_id, members) = row?;
+            rooms.insert(
+                room_id,
+                RoomState {
+                    members: decode_members(&members)?,
+                },
+            );
+        }
+
+        let mut clients = HashMap::new();
+        let mut stmt = conn.prepare("SELECT client_id, room_id FROM clients")?;
+        let rows = stmt.query_map([], |row| {
+            let client_id: String = row.get(0)?;
+            let room_id: i64 = row.get(1)?;
+            Ok((client_id, room_id as RoomId))
+        })?;
+        for row in rows {
+            let (client_id, room_id) = row?;
+            clients.insert(client_id.parse()?, ClientState::new(room_id));
+        }
+
+        Ok((rooms, clients))
+    }
+
+    pub fn put_room(&self, room_id: RoomId, room: &RoomState) -> anyhow::Result<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "INSERT INTO rooms (room_id, members) VALUES (?1, ?2)
+             ON CONFLICT(room_id) DO UPDATE SET members = excluded.members",
+            params![room_id as i64, encode_members(&room.members)],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_room(&self, room_id: RoomId) -> anyhow::Result<()> {
+        self.pool
+            .get()?
+            .execute("DELETE FROM rooms WHERE room_id = ?1", params![room_id as i64])?;
+        Ok(())
+    }
+
+    pub fn put_client(&self, client_id: ClientId, client: &ClientState) -> anyhow::Result<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "INSERT INTO clients (client_id, room_id) VALUES (?1, ?2)
+             ON CONFLICT(client_id) DO UPDATE SET room_id = excluded.room_id",
+            params![client_id.to_string(), client.room as i64],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_client(&self, client_id: ClientId) -> anyhow::Result<()> {
+        self.pool
+            .get()?
+            .execute("DELETE FROM clients WHERE client_id = ?1", params![client_id.to_string()])?;
+        Ok(())
+    }
+}